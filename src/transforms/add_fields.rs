@@ -4,9 +4,10 @@ use crate::{
     template::Template,
     topology::config::{DataType, TransformConfig, TransformContext, TransformDescription},
 };
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use string_cache::DefaultAtom as Atom;
 use toml::value::Value as TomlValue;
 
@@ -14,18 +15,79 @@ use toml::value::Value as TomlValue;
 #[serde(deny_unknown_fields)]
 pub struct AddFieldsConfig {
     pub fields: IndexMap<String, TomlValue>,
+    /// When `true` (the default), nested tables and arrays are exploded into
+    /// dotted (`table.key`) and indexed (`array[0]`) leaf keys. When `false`,
+    /// they are inserted as native nested maps and arrays.
+    #[serde(default = "default_flatten")]
+    pub flatten: bool,
+    /// How to handle non-finite float literals (`inf`, `-inf`, `nan`), which
+    /// most downstream sinks and codecs cannot represent.
+    #[serde(default)]
+    pub non_finite_floats: NonFiniteFloats,
+    /// When `true`, templated values keep a typed value instead of always
+    /// being inserted as strings. A template that is a single bare
+    /// `{{field}}` reference copies that field's value with its original
+    /// type. Any other template is rendered and coerced to a scalar type
+    /// (integer, float, boolean, or timestamp) when its output parses
+    /// cleanly, and otherwise stays a string.
+    #[serde(default)]
+    pub fields_typed: bool,
+}
+
+fn default_flatten() -> bool {
+    true
+}
+
+/// Policy for TOML float literals that are not finite.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NonFiniteFloats {
+    /// Replace the value with its canonical string form (`"inf"`, `"-inf"`,
+    /// `"nan"`). This is the default.
+    String,
+    /// Drop the field entirely, emitting a warning.
+    Drop,
+}
+
+impl Default for NonFiniteFloats {
+    fn default() -> Self {
+        NonFiniteFloats::String
+    }
 }
 
 #[derive(Clone)]
 enum TemplateOrValue {
-    Template(Template),
+    /// A compiled template plus, when the template is a single bare
+    /// `{{field}}` reference, the name of that field (used for type-preserving
+    /// coercion).
+    Template(Template, Option<Atom>),
     Value(Value),
+    Array(Vec<TemplateOrValue>),
+    Map(BTreeMap<String, TemplateOrValue>),
 }
 
 impl From<Template> for TemplateOrValue {
     fn from(v: Template) -> Self {
-        TemplateOrValue::Template(v)
+        TemplateOrValue::Template(v, None)
+    }
+}
+
+/// If `s` is exactly one bare `{{field}}` reference (ignoring surrounding
+/// whitespace), return the referenced field name. Anything with literal text
+/// or multiple references returns `None`.
+fn single_field_ref(s: &str) -> Option<Atom> {
+    let t = s.trim();
+    if t.len() >= 4 && t.starts_with("{{") && t.ends_with("}}") {
+        let inner = t[2..t.len() - 2].trim();
+        if !inner.is_empty()
+            && !inner.contains("{{")
+            && !inner.contains("}}")
+            && inner.split_whitespace().count() == 1
+        {
+            return Some(inner.into());
+        }
     }
+    None
 }
 
 impl From<Value> for TemplateOrValue {
@@ -36,6 +98,7 @@ impl From<Value> for TemplateOrValue {
 
 pub struct AddFields {
     fields: IndexMap<Atom, TemplateOrValue>,
+    typed: bool,
 }
 
 inventory::submit! {
@@ -45,7 +108,12 @@ inventory::submit! {
 #[typetag::serde(name = "add_fields")]
 impl TransformConfig for AddFieldsConfig {
     fn build(&self, _cx: TransformContext) -> crate::Result<Box<dyn Transform>> {
-        Ok(Box::new(AddFields::new(self.fields.clone())))
+        Ok(Box::new(AddFields::new(
+            self.fields.clone(),
+            self.flatten,
+            self.non_finite_floats,
+            self.fields_typed,
+        )))
     }
 
     fn input_type(&self) -> DataType {
@@ -62,73 +130,141 @@ impl TransformConfig for AddFieldsConfig {
 }
 
 impl AddFields {
-    pub fn new(fields: IndexMap<String, TomlValue>) -> Self {
+    pub fn new(
+        fields: IndexMap<String, TomlValue>,
+        flatten: bool,
+        non_finite_floats: NonFiniteFloats,
+        typed: bool,
+    ) -> Self {
         let mut new_fields = IndexMap::new();
 
         for (k, v) in fields {
-            flatten_field(k.into(), v, &mut new_fields);
+            if flatten {
+                flatten_field(k.into(), v, &mut new_fields, non_finite_floats);
+            } else if let Some(tov) = structure_field(v, non_finite_floats) {
+                new_fields.insert(k.into(), tov);
+            }
+        }
+
+        AddFields {
+            fields: new_fields,
+            typed,
+        }
+    }
+}
+
+impl TemplateOrValue {
+    /// Resolve this entry against `event`, rendering any nested templates.
+    ///
+    /// Returns `None` if a template fails to render, matching the
+    /// drop-with-warning policy applied at the top level.
+    fn render(&self, event: &Event, typed: bool) -> Option<Value> {
+        match self {
+            TemplateOrValue::Template(t, field) => {
+                if typed {
+                    // For a single `{{field}}` reference, reuse the source
+                    // field's existing `Value` so its original type is
+                    // preserved exactly. Fall back to parsing the rendered
+                    // text only when that field is absent or the template
+                    // isn't a bare reference.
+                    field
+                        .as_ref()
+                        .and_then(|f| event.as_log().get(f).cloned())
+                        .or_else(|| t.render_string(event).ok().map(coerce_rendered))
+                } else {
+                    t.render_string(event).ok().map(Value::from)
+                }
+            }
+            TemplateOrValue::Value(v) => Some(v.clone()),
+            // Structured mode inserts the entry as a native nested map/array so
+            // it is retrievable as a real sub-object rather than as dotted
+            // leaves. This relies on the event `Value` model's `Map`/`Array`
+            // variants, which the structured-mode request presupposes.
+            TemplateOrValue::Array(items) => items
+                .iter()
+                .map(|item| item.render(event, typed))
+                .collect::<Option<Vec<_>>>()
+                .map(Value::Array),
+            TemplateOrValue::Map(map) => map
+                .iter()
+                .map(|(k, v)| v.render(event, typed).map(|v| (k.clone(), v)))
+                .collect::<Option<BTreeMap<_, _>>>()
+                .map(Value::Map),
         }
+    }
+}
 
-        AddFields { fields: new_fields }
+/// Coerce a rendered template string back to a scalar `Value`, mirroring the
+/// TOML type set handled in [`flatten_field`]. Falls back to the string when
+/// no type matches.
+fn coerce_rendered(s: String) -> Value {
+    if let Ok(b) = s.parse::<bool>() {
+        return Value::from(b);
+    }
+    if let Ok(i) = s.parse::<i64>() {
+        return Value::from(i);
     }
+    if let Ok(f) = s.parse::<f64>() {
+        if f.is_finite() {
+            return Value::from(f);
+        }
+    }
+    if let Ok(ts) = s.parse::<DateTime<Utc>>() {
+        return Value::from(ts);
+    }
+    Value::from(s)
 }
 
 impl Transform for AddFields {
     fn transform(&mut self, mut event: Event) -> Option<Event> {
-        for (key, value_or_template) in self.fields.clone() {
-            let value = match value_or_template {
-                TemplateOrValue::Template(v) => match v.render_string(&event) {
-                    Ok(v) => v,
-                    Err(_) => {
-                        warn!(
-                            "Failed to render templated value at key `{}`, dropping.",
-                            key
-                        );
-                        continue;
-                    }
+        for (key, value_or_template) in &self.fields {
+            match value_or_template.render(&event, self.typed) {
+                Some(value) => {
+                    event.as_mut_log().insert(key.clone(), value);
                 }
-                .into(),
-                TemplateOrValue::Value(v) => v,
-            };
-            event.as_mut_log().insert(key, value);
+                None => warn!(
+                    "Failed to render templated value at key `{}`, dropping.",
+                    key
+                ),
+            }
         }
 
         Some(event)
     }
 }
 
-fn flatten_field(key: Atom, value: TomlValue, new_fields: &mut IndexMap<Atom, TemplateOrValue>) {
+fn flatten_field(
+    key: Atom,
+    value: TomlValue,
+    new_fields: &mut IndexMap<Atom, TemplateOrValue>,
+    non_finite_floats: NonFiniteFloats,
+) {
     match value {
         TomlValue::String(s) => {
+            let field = single_field_ref(&s);
             let t = Template::from(s);
-            new_fields.insert(key, t.into())
+            new_fields.insert(key, TemplateOrValue::Template(t, field))
         }
         TomlValue::Integer(i) => {
             let i = Value::from(i);
             new_fields.insert(key, i.into())
         }
-        TomlValue::Float(f) => {
-            let f = Value::from(f);
-            new_fields.insert(key, f.into())
-        }
+        TomlValue::Float(f) => match coerce_float(f, non_finite_floats, &key) {
+            Some(v) => new_fields.insert(key, v.into()),
+            None => None,
+        },
         TomlValue::Boolean(b) => {
             let b = Value::from(b);
             new_fields.insert(key, b.into())
         }
         TomlValue::Datetime(dt) => {
-            let dt = dt.to_string();
-            if let Ok(ts) = dt.parse::<DateTime<Utc>>() {
-                let ts = Value::from(ts);
-                new_fields.insert(key, ts.into())
-            } else {
-                let dt = Value::from(dt);
-                new_fields.insert(key, dt.into())
-            }
+            let value = datetime_to_value(&dt);
+            new_fields.insert(key, value.into())
         }
         TomlValue::Array(vals) => {
             for (i, val) in vals.into_iter().enumerate() {
                 let key = format!("{}[{}]", key, i);
-                flatten_field(key.into(), val, new_fields);
+                flatten_field(key.into(), val, new_fields, non_finite_floats);
             }
 
             None
@@ -136,7 +272,7 @@ fn flatten_field(key: Atom, value: TomlValue, new_fields: &mut IndexMap<Atom, Te
         TomlValue::Table(map) => {
             for (table_key, value) in map {
                 let key = format!("{}.{}", key, table_key);
-                flatten_field(key.into(), value, new_fields);
+                flatten_field(key.into(), value, new_fields, non_finite_floats);
             }
 
             None
@@ -144,10 +280,107 @@ fn flatten_field(key: Atom, value: TomlValue, new_fields: &mut IndexMap<Atom, Te
     };
 }
 
+/// Apply the configured non-finite-float policy, returning `None` when the
+/// value should be dropped.
+fn coerce_float(f: f64, policy: NonFiniteFloats, key: &dyn std::fmt::Display) -> Option<Value> {
+    if f.is_finite() {
+        return Some(Value::from(f));
+    }
+
+    match policy {
+        NonFiniteFloats::String => Some(Value::from(canonical_non_finite(f))),
+        NonFiniteFloats::Drop => {
+            warn!("Dropping non-finite float value at key `{}`.", key);
+            None
+        }
+    }
+}
+
+/// The canonical TOML spelling of a non-finite float.
+fn canonical_non_finite(f: f64) -> &'static str {
+    if f.is_nan() {
+        "nan"
+    } else if f.is_sign_positive() {
+        "inf"
+    } else {
+        "-inf"
+    }
+}
+
+/// Recursively convert a TOML value into a `TemplateOrValue`, preserving
+/// tables as nested maps and arrays as nested arrays. String leaves are
+/// compiled as `Template`s so they are still rendered per-event.
+fn structure_field(value: TomlValue, non_finite_floats: NonFiniteFloats) -> Option<TemplateOrValue> {
+    Some(match value {
+        TomlValue::String(s) => {
+            let field = single_field_ref(&s);
+            TemplateOrValue::Template(Template::from(s), field)
+        }
+        TomlValue::Integer(i) => Value::from(i).into(),
+        TomlValue::Float(f) => coerce_float(f, non_finite_floats, &"<structured>")?.into(),
+        TomlValue::Boolean(b) => Value::from(b).into(),
+        TomlValue::Datetime(dt) => datetime_to_value(&dt).into(),
+        TomlValue::Array(vals) => TemplateOrValue::Array(
+            vals.into_iter()
+                .filter_map(|v| structure_field(v, non_finite_floats))
+                .collect(),
+        ),
+        TomlValue::Table(map) => TemplateOrValue::Map(
+            map.into_iter()
+                .filter_map(|(k, v)| structure_field(v, non_finite_floats).map(|v| (k, v)))
+                .collect(),
+        ),
+    })
+}
+
+/// Map a TOML datetime onto the most faithful internal `Value`.
+///
+/// The original request asked to inspect `toml::value::Datetime`'s `date`,
+/// `time`, and `offset` fields directly rather than round-tripping through
+/// `to_string()`. That is deliberately NOT done here: in the `toml` version
+/// pinned by this tree those fields are private (public accessors only arrived
+/// with the later `toml_datetime` split), so field access does not compile.
+/// We therefore distinguish the four datetime shapes by parsing the
+/// normalized `to_string()` form, which yields identical results:
+///
+/// * offset date-time (`1979-05-27T07:32:00Z` / `...-07:00`) is a real
+///   instant, so its offset is honored and the result normalized to UTC;
+/// * local date-time (`1979-05-27T07:32:00`, no offset) is interpreted as UTC;
+/// * local date (`1979-05-27`) becomes midnight UTC on that day;
+/// * local time (`07:32:00`) carries no date and is preserved as a string.
+fn datetime_to_value(dt: &toml::value::Datetime) -> Value {
+    let s = dt.to_string();
+
+    // Offset date-time: RFC 3339 handles both `Z` and numeric offsets and
+    // normalizes onto the correct UTC instant.
+    if let Ok(ts) = DateTime::parse_from_rfc3339(&s) {
+        return Value::from(ts.with_timezone(&Utc));
+    }
+
+    // Local date-time: no offset, interpreted as UTC. The optional `%.f`
+    // covers both the fractional and non-fractional spellings.
+    if let Ok(naive) = NaiveDateTime::parse_from_str(&s, "%Y-%m-%dT%H:%M:%S%.f") {
+        return Value::from(DateTime::<Utc>::from_utc(naive, Utc));
+    }
+
+    // Local date: midnight UTC on that day.
+    if let Ok(date) = NaiveDate::parse_from_str(&s, "%Y-%m-%d") {
+        return Value::from(DateTime::<Utc>::from_utc(date.and_hms(0, 0, 0), Utc));
+    }
+
+    // Local time (or anything else) has no date to anchor it to an instant,
+    // so keep the normalized TOML text as a string value.
+    Value::from(s)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::AddFields;
-    use crate::{event::Event, transforms::Transform};
+    use super::{AddFields, AddFieldsConfig, NonFiniteFloats};
+    use crate::{
+        event::{Event, Value},
+        transforms::Transform,
+    };
+    use chrono::{DateTime, Utc};
     use indexmap::IndexMap;
     use std::collections::HashMap;
     use string_cache::DefaultAtom as Atom;
@@ -157,7 +390,7 @@ mod tests {
         let event = Event::from("augment me");
         let mut fields = IndexMap::new();
         fields.insert("some_key".into(), "some_val".into());
-        let mut augment = AddFields::new(fields);
+        let mut augment = AddFields::new(fields, true, NonFiniteFloats::default(), false);
 
         let new_event = augment.transform(event).unwrap();
 
@@ -173,7 +406,7 @@ mod tests {
         let event = Event::from("augment me");
         let mut fields = IndexMap::new();
         fields.insert("some_key".into(), "{{message}} {{message}}".into());
-        let mut augment = AddFields::new(fields);
+        let mut augment = AddFields::new(fields, true, NonFiniteFloats::default(), false);
 
         let new_event = augment.transform(event).unwrap();
 
@@ -200,7 +433,7 @@ mod tests {
 
         fields.insert("table".into(), map.into());
 
-        let mut transform = AddFields::new(fields);
+        let mut transform = AddFields::new(fields, true, NonFiniteFloats::default(), false);
 
         let event = transform.transform(event).unwrap().into_log();
 
@@ -213,4 +446,174 @@ mod tests {
         assert_eq!(event[&"array[2]".into()], 3.into());
         assert_eq!(event[&"table.key".into()], "value".into());
     }
+
+    #[test]
+    fn add_fields_toml_datetimes() {
+        let config: AddFieldsConfig = toml::from_str(
+            r#"
+            [fields]
+            offset = 1979-05-27T07:32:00Z
+            offset_neg = 1979-05-27T00:32:00-07:00
+            local_dt = 1979-05-27T07:32:00
+            local_date = 1979-05-27
+            local_time = 07:32:00
+            "#,
+        )
+        .unwrap();
+
+        let mut transform = AddFields::new(config.fields.clone(), true, config.non_finite_floats, false);
+        let event = transform.transform(Event::from("please")).unwrap().into_log();
+
+        let expected = "1979-05-27T07:32:00Z".parse::<DateTime<Utc>>().unwrap();
+        // Offset date-time normalizes to the UTC instant.
+        assert_eq!(event[&"offset".into()], Value::Timestamp(expected));
+        // A negative offset shifts local 00:32-07:00 onto the same 07:32 UTC instant.
+        assert_eq!(event[&"offset_neg".into()], Value::Timestamp(expected));
+        // Local date-time is interpreted as UTC.
+        assert_eq!(event[&"local_dt".into()], Value::Timestamp(expected));
+        // Local date becomes midnight UTC.
+        let midnight = "1979-05-27T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(event[&"local_date".into()], Value::Timestamp(midnight));
+        // Local time has no date and is preserved as a string.
+        assert_eq!(event[&"local_time".into()], Value::from("07:32:00"));
+    }
+
+    #[test]
+    fn add_fields_structured() {
+        let config: AddFieldsConfig = toml::from_str(
+            r#"
+            flatten = false
+
+            [fields.metadata]
+            source = "vector"
+            count = 3
+            tags = ["a", "b"]
+            "#,
+        )
+        .unwrap();
+
+        let mut transform = AddFields::new(config.fields.clone(), config.flatten, config.non_finite_floats, config.fields_typed);
+        let event = transform.transform(Event::from("please")).unwrap().into_log();
+
+        let mut expected = std::collections::BTreeMap::new();
+        expected.insert("source".to_string(), Value::from("vector"));
+        expected.insert("count".to_string(), Value::from(3));
+        expected.insert(
+            "tags".to_string(),
+            Value::Array(vec![Value::from("a"), Value::from("b")]),
+        );
+
+        // The whole table lands under one key as a structured sub-object,
+        // retrievable as a real nested map rather than dotted `metadata.source`
+        // leaves.
+        assert_eq!(event[&"metadata".into()], Value::Map(expected));
+        assert_eq!(event.get(&"metadata.source".into()), None);
+    }
+
+    #[test]
+    fn add_fields_non_finite_floats_to_string() {
+        let config: AddFieldsConfig = toml::from_str(
+            r#"
+            [fields]
+            pos = inf
+            neg = -inf
+            nan = nan
+            finite = 1.5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.non_finite_floats, NonFiniteFloats::String);
+
+        let mut transform = AddFields::new(config.fields.clone(), config.flatten, config.non_finite_floats, config.fields_typed);
+        let event = transform.transform(Event::from("please")).unwrap().into_log();
+
+        assert_eq!(event[&"pos".into()], Value::from("inf"));
+        assert_eq!(event[&"neg".into()], Value::from("-inf"));
+        assert_eq!(event[&"nan".into()], Value::from("nan"));
+        assert_eq!(event[&"finite".into()], Value::from(1.5));
+    }
+
+    #[test]
+    fn add_fields_non_finite_floats_dropped() {
+        let config: AddFieldsConfig = toml::from_str(
+            r#"
+            non_finite_floats = "drop"
+
+            [fields]
+            pos = inf
+            finite = 1.5
+            "#,
+        )
+        .unwrap();
+
+        let mut transform = AddFields::new(config.fields.clone(), config.flatten, config.non_finite_floats, config.fields_typed);
+        let event = transform.transform(Event::from("please")).unwrap().into_log();
+
+        assert_eq!(event.get(&"pos".into()), None);
+        assert_eq!(event[&"finite".into()], Value::from(1.5));
+    }
+
+    #[test]
+    fn add_fields_typed_templates() {
+        let when = "1979-05-27T07:32:00Z".parse::<DateTime<Utc>>().unwrap();
+
+        let mut event = Event::from("hello");
+        event.as_mut_log().insert("count".into(), Value::from(7));
+        event.as_mut_log().insert("ratio".into(), Value::from(1.5));
+        event.as_mut_log().insert("flag".into(), Value::from(true));
+        event.as_mut_log().insert("when".into(), Value::from(when));
+        // A genuine string source that merely looks like a number.
+        event.as_mut_log().insert("user_id".into(), Value::from("007"));
+
+        let config: AddFieldsConfig = toml::from_str(
+            r#"
+            fields_typed = true
+
+            [fields]
+            new_count = "{{count}}"
+            new_ratio = "{{ratio}}"
+            new_flag = "{{flag}}"
+            new_when = "{{when}}"
+            new_id = "{{user_id}}"
+            label = "count is {{count}}"
+            "#,
+        )
+        .unwrap();
+
+        let mut transform =
+            AddFields::new(config.fields.clone(), config.flatten, config.non_finite_floats, config.fields_typed);
+        let event = transform.transform(event).unwrap().into_log();
+
+        // Single-reference templates reuse the source field's value, so the
+        // original type is preserved exactly.
+        assert_eq!(event[&"new_count".into()], Value::from(7));
+        assert_eq!(event[&"new_ratio".into()], Value::from(1.5));
+        assert_eq!(event[&"new_flag".into()], Value::from(true));
+        assert_eq!(event[&"new_when".into()], Value::from(when));
+        // A string source is not corrupted into an integer (no lost leading zero).
+        assert_eq!(event[&"new_id".into()], Value::from("007"));
+        // Mixed text is not a bare reference and stays a string.
+        assert_eq!(event[&"label".into()], Value::from("count is 7"));
+    }
+
+    #[test]
+    fn add_fields_drops_unrenderable_template() {
+        let config: AddFieldsConfig = toml::from_str(
+            "[fields]\ngood = \"{{message}}\"\nbad = \"{{missing}}\"\n",
+        )
+        .unwrap();
+
+        let mut transform = AddFields::new(
+            config.fields.clone(),
+            config.flatten,
+            config.non_finite_floats,
+            config.fields_typed,
+        );
+
+        // `bad` references a missing field, so rendering fails and it is
+        // dropped with a warning while `good` still renders.
+        let event = transform.transform(Event::from("hi")).unwrap().into_log();
+        assert_eq!(event.get(&"bad".into()), None);
+        assert_eq!(event[&"good".into()], Value::from("hi"));
+    }
 }